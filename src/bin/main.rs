@@ -1,6 +1,6 @@
 use std::fs;
 use serde_json::json;
-use websrv::{WebSrv, StatusCode, Method, Route, Request};
+use websrv::{WebSrv, StatusCode, Method, Route, Request, HttpResponse};
 
 static ROUTES: &'static [Route] = &[
     Route {path: "/hello", method: Method::GET, func: hello_world},
@@ -10,26 +10,26 @@ static ROUTES: &'static [Route] = &[
 
 fn main() {
     env_logger::init();
-    let websrv = WebSrv::new("127.0.0.1:7878", 4);
+    let websrv = WebSrv::new("127.0.0.1:7878", 4, 30);
 
     websrv.run(ROUTES);
 }
 
-fn hello_world(_: &Request) -> (StatusCode, String) {
+fn hello_world(_: &Request) -> HttpResponse {
     let response = fs::read_to_string("templates/hello.html").unwrap();
-    (StatusCode::Ok, response)
+    HttpResponse::with_status(StatusCode::Ok).body(response)
 }
 
-fn hello_rust(request: &Request) -> (StatusCode, String) {
-    (StatusCode::Created, json!({
+fn hello_rust(request: &Request) -> HttpResponse {
+    HttpResponse::with_status(StatusCode::Created).json(&json!({
         "message": "Hello from rust!",
         "method": request.method,
         "uri": request.uri,
         "http_ver": request.http_ver
-    }).to_string())
+    }))
 }
 
-fn forbidden(_: &Request) -> (StatusCode, String) {
+fn forbidden(_: &Request) -> HttpResponse {
     let response = fs::read_to_string("templates/403.html").unwrap();
-    (StatusCode::Forbidden, response)
-}
\ No newline at end of file
+    HttpResponse::with_status(StatusCode::Forbidden).body(response)
+}
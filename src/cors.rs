@@ -0,0 +1,80 @@
+use std::collections::HashSet;
+
+use crate::{Method, Middleware, Request, HttpResponse, StatusCode};
+
+/// Built-in CORS middleware: allow-lists origins, methods and headers, and
+/// answers `OPTIONS` preflight requests from allowed origins without
+/// reaching the route. An `OPTIONS` request from any other origin, or with
+/// no `Origin` header, is routed like any other request.
+///
+/// The allowed origin is always echoed back verbatim (never a blanket
+/// `"*"`), so it's safe to combine with credentialed requests.
+pub struct Cors {
+    allowed_origins: HashSet<String>,
+    allowed_methods: Vec<String>,
+    allowed_headers: Vec<String>,
+}
+
+impl Cors {
+    pub fn new() -> Cors {
+        Cors {
+            allowed_origins: HashSet::new(),
+            allowed_methods: Vec::new(),
+            allowed_headers: Vec::new(),
+        }
+    }
+
+    pub fn allow_origin(mut self, origin: &str) -> Cors {
+        self.allowed_origins.insert(String::from(origin));
+        self
+    }
+
+    pub fn allow_method(mut self, method: &str) -> Cors {
+        self.allowed_methods.push(String::from(method));
+        self
+    }
+
+    pub fn allow_header(mut self, header: &str) -> Cors {
+        self.allowed_headers.push(String::from(header));
+        self
+    }
+
+    fn cors_headers(&self, origin: &str) -> Vec<(String, String)> {
+        vec![
+            (String::from("Access-Control-Allow-Origin"), String::from(origin)),
+            (String::from("Access-Control-Allow-Methods"), self.allowed_methods.join(", ")),
+            (String::from("Access-Control-Allow-Headers"), self.allowed_headers.join(", ")),
+        ]
+    }
+}
+
+impl Default for Cors {
+    fn default() -> Cors {
+        Cors::new()
+    }
+}
+
+impl Middleware for Cors {
+    fn before(&self, req: &mut Request) -> Option<HttpResponse> {
+        let origin = req.header("Origin")?;
+        if !self.allowed_origins.contains(origin) {
+            return None;
+        }
+
+        if req.method != Method::OPTIONS {
+            return None;
+        }
+
+        // Headers are left to `after`, which runs unconditionally (even for
+        // this short-circuit) so there's exactly one place that attaches them.
+        Some(HttpResponse::with_status(StatusCode::NoContent))
+    }
+
+    fn after(&self, req: &Request, resp: &mut HttpResponse) {
+        if let Some(origin) = req.header("Origin") {
+            if self.allowed_origins.contains(origin) {
+                resp.headers.extend(self.cors_headers(origin));
+            }
+        }
+    }
+}
@@ -1,3 +1,4 @@
+use std::collections::HashMap;
 use std::io::prelude::*;
 use std::net::TcpListener;
 use std::net::TcpStream;
@@ -5,9 +6,92 @@ use std::sync::mpsc;
 use std::sync::Arc;
 use std::sync::Mutex;
 use std::thread;
+use std::time::{Duration, Instant};
 use serde::Serialize;
 use log::{debug, info};
 
+pub mod cors;
+pub mod test_server;
+
+/// How long we'll wait for a request's header block to fully arrive before
+/// giving up on a slow or stalled client.
+const SLOW_REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How a request body is framed, per its headers.
+enum BodyKind {
+    /// No body is expected.
+    None,
+    /// `Content-Length`: exactly this many bytes follow the header block.
+    Length(usize),
+    /// `Transfer-Encoding: chunked`.
+    Chunked,
+}
+
+/// Reads `Content-Length`/`Transfer-Encoding` straight out of a raw header
+/// block, skipping the request line.
+fn body_kind_of(head: &[u8]) -> BodyKind {
+    let head = String::from_utf8_lossy(head);
+    let mut length = None;
+    let mut chunked = false;
+
+    for line in head.lines().skip(1) {
+        let mut kv = line.splitn(2, ": ");
+        let key = kv.next().unwrap_or("");
+        let value = kv.next().unwrap_or("").trim();
+
+        if key.eq_ignore_ascii_case("Transfer-Encoding") && value.eq_ignore_ascii_case("chunked") {
+            chunked = true;
+        } else if key.eq_ignore_ascii_case("Content-Length") {
+            length = value.parse::<usize>().ok();
+        }
+    }
+
+    if chunked {
+        BodyKind::Chunked
+    } else if let Some(n) = length {
+        BodyKind::Length(n)
+    } else {
+        BodyKind::None
+    }
+}
+
+/// Finds the end of the header block (the index just past the blank line
+/// that terminates it), if it's fully present in `buffer` yet.
+fn find_header_end(buffer: &[u8]) -> Option<usize> {
+    buffer
+        .windows(4)
+        .position(|w| w == b"\r\n\r\n")
+        .map(|i| i + 4)
+}
+
+/// Decodes a chunked-transfer body. Returns `None` if the framing isn't
+/// fully present in `data` yet (the caller should keep reading), otherwise
+/// the decoded body plus how many bytes of `data` the framing consumed, so
+/// any trailing bytes (e.g. a pipelined next request) can be told apart.
+fn decode_chunked(data: &[u8]) -> Option<(Vec<u8>, usize)> {
+    let mut pos = 0;
+    let mut body = Vec::new();
+
+    loop {
+        let line_len = data[pos..].windows(2).position(|w| w == b"\r\n")?;
+        let size_line = std::str::from_utf8(&data[pos..pos + line_len]).ok()?;
+        let size_str = size_line.split(';').next().unwrap_or(size_line).trim();
+        let size = usize::from_str_radix(size_str, 16).ok()?;
+        pos += line_len + 2;
+
+        if size == 0 {
+            return if data.len() >= pos + 2 { Some((body, pos + 2)) } else { None };
+        }
+
+        if data.len() < pos + size + 2 {
+            return None;
+        }
+
+        body.extend_from_slice(&data[pos..pos + size]);
+        pos += size + 2;
+    }
+}
+
 
 pub struct ThreadPool {
     workers: Vec<Worker>,
@@ -100,64 +184,294 @@ impl Drop for ThreadPool {
 pub struct WebSrv {
     addr: String,
     workers: usize,
+    keep_alive: Duration,
+    middlewares: Vec<Box<dyn Middleware + Send + Sync>>,
+}
+
+/// Cross-cutting logic that runs around every request, e.g. logging, auth
+/// or CORS. Registered on a `WebSrv` with `WebSrv::wrap`.
+pub trait Middleware {
+    /// Runs before the matched route. Returning `Some` short-circuits the
+    /// route and is sent as the response instead.
+    fn before(&self, req: &mut Request) -> Option<HttpResponse> {
+        let _ = req;
+        None
+    }
+
+    /// Runs after the route (or a short-circuiting `before`) has produced a
+    /// response, in reverse registration order, with a chance to amend it.
+    fn after(&self, req: &Request, resp: &mut HttpResponse) {
+        let _ = (req, resp);
+    }
+}
+
+/// A response being built up by a route or middleware: a status, an
+/// optional body, and any headers attached along the way.
+pub struct HttpResponse {
+    pub status: StatusCode,
+    pub body: String,
+    pub headers: Vec<(String, String)>,
+}
+
+impl HttpResponse {
+    pub fn with_status(status: StatusCode) -> HttpResponse {
+        HttpResponse { status, body: String::new(), headers: Vec::new() }
+    }
+
+    pub fn header(mut self, key: &str, value: &str) -> HttpResponse {
+        self.headers.push((String::from(key), String::from(value)));
+        self
+    }
+
+    pub fn body(mut self, body: impl Into<String>) -> HttpResponse {
+        self.body = body.into();
+        self
+    }
+
+    /// Sets the body to `value` serialized as JSON and the `Content-Type`
+    /// header accordingly.
+    pub fn json(self, value: &impl Serialize) -> HttpResponse {
+        let body = serde_json::to_string(value).unwrap();
+        self.header("Content-Type", "application/json").body(body)
+    }
+}
+
+/// Outcome of trying to read one request's worth of headers off a
+/// connection that may be freshly accepted or sitting idle after a
+/// previous response.
+enum ReadOutcome {
+    /// A full header block and body were read. Any bytes read past the end
+    /// of this request (e.g. a pipelined next request) are carried along so
+    /// the next call to `read_request` can pick up where this one left off.
+    Request(Request, Vec<u8>),
+    /// The peer closed the connection, or sent something we can't parse.
+    Closed,
+    /// Nothing arrived before the keep-alive idle timeout elapsed.
+    Idle,
+    /// Bytes started arriving but the request never finished arriving within
+    /// the slow-request window.
+    SlowRequest,
 }
 
 impl WebSrv {
-    pub fn new(addr: &str, workers: usize) -> WebSrv {
+    pub fn new(addr: &str, workers: usize, keep_alive_secs: u64) -> WebSrv {
         WebSrv {
             addr: String::from(addr),
             workers,
+            keep_alive: Duration::from_secs(keep_alive_secs),
+            middlewares: Vec::new(),
         }
     }
 
-    pub fn run(&self, routes: &'static [Route]) {
+    /// Registers a middleware to run around every request. Middlewares run
+    /// in registration order for `before` and reverse order for `after`.
+    pub fn wrap(mut self, middleware: impl Middleware + Send + Sync + 'static) -> WebSrv {
+        self.middlewares.push(Box::new(middleware));
+        self
+    }
+
+    pub fn run(self, routes: &'static [Route]) {
         let listener = TcpListener::bind(self.addr.to_string()).unwrap();
         let pool = ThreadPool::new(self.workers);
+        let keep_alive = self.keep_alive;
+        let middlewares = Arc::new(self.middlewares);
         for stream in listener.incoming() {
             let stream = stream.unwrap();
+            let middlewares = Arc::clone(&middlewares);
             pool.execute(move || {
-                WebSrv::handle_connection(stream, routes);
+                WebSrv::handle_connection(stream, routes, keep_alive, middlewares);
             });
         }
     }
 
-    fn handle_connection(mut stream: TcpStream, routes: &'static [Route]) {
-        let mut buffer = [0; 4096];
-        stream.read(&mut buffer).unwrap();
-        let request_body = String::from_utf8_lossy(&buffer[..]);
-        let request = Request::from_str(&request_body);
-        if request.is_none() {
-            return;
+    pub(crate) fn handle_connection(
+        mut stream: TcpStream,
+        routes: &'static [Route],
+        keep_alive: Duration,
+        middlewares: Arc<Vec<Box<dyn Middleware + Send + Sync>>>,
+    ) {
+        let mut leftover = Vec::new();
+
+        loop {
+            match WebSrv::read_request(&mut stream, keep_alive, leftover) {
+                ReadOutcome::Closed | ReadOutcome::Idle => return,
+                ReadOutcome::SlowRequest => {
+                    WebSrv::write_response(&mut stream, &HttpResponse::with_status(StatusCode::RequestTimeout), false);
+                    return;
+                }
+                ReadOutcome::Request(mut request, rest) => {
+                    leftover = rest;
+                    let keep_alive_wanted = request.wants_keep_alive();
+                    WebSrv::dispatch(&mut stream, routes, &mut request, keep_alive_wanted, &middlewares);
+
+                    if !keep_alive_wanted {
+                        return;
+                    }
+                }
+            }
         }
-        let request = request.unwrap();
+    }
 
-        for route in routes {
-            if route.path == request.uri && request.method == route.method {
-                let (status_code, response) = (route.func)(&request);
-                let mut response_request = String::new();
+    /// Reads one request off `stream`, seeded with any bytes already read
+    /// past the previous request's boundary (e.g. a pipelined next request).
+    /// Applies `keep_alive` while waiting for the first byte of a new
+    /// request and `SLOW_REQUEST_TIMEOUT` once bytes have started arriving:
+    /// the whole header block must arrive within one window of its first
+    /// byte, while each read of the body gets a fresh window so a
+    /// slow-but-progressing body isn't killed partway through.
+    fn read_request(stream: &mut TcpStream, keep_alive: Duration, initial: Vec<u8>) -> ReadOutcome {
+        let mut deadline = if initial.is_empty() { None } else { Some(Instant::now() + SLOW_REQUEST_TIMEOUT) };
+        let mut buffer = initial;
+        let mut chunk = [0; 4096];
+        let mut header_end: Option<usize> = None;
+
+        loop {
+            if header_end.is_none() {
+                header_end = find_header_end(&buffer);
+            }
 
-                response_request.push_str(format!("HTTP/1.1 {}\r\n", status_code.text()).as_str());
+            if let Some(header_end) = header_end {
+                if WebSrv::body_complete(&buffer[..header_end], &buffer[header_end..]) {
+                    break;
+                }
+            }
 
-                if response.len() > 0 {
-                    response_request.push_str(format!("Content-Length: {}\r\n\r\n", response.len().to_string()).as_str());
-                    response_request.push_str(response.as_str());
+            let timeout = match deadline {
+                Some(deadline) => {
+                    let remaining = deadline.saturating_duration_since(Instant::now());
+                    if remaining.is_zero() {
+                        return ReadOutcome::SlowRequest;
+                    }
+                    remaining
+                }
+                None => keep_alive,
+            };
+            stream.set_read_timeout(Some(timeout)).unwrap();
+
+            match stream.read(&mut chunk) {
+                Ok(0) => return ReadOutcome::Closed,
+                Ok(n) => {
+                    buffer.extend_from_slice(&chunk[..n]);
+                    if deadline.is_none() || header_end.is_some() {
+                        deadline = Some(Instant::now() + SLOW_REQUEST_TIMEOUT);
+                    }
+                }
+                Err(ref e)
+                    if e.kind() == std::io::ErrorKind::WouldBlock
+                        || e.kind() == std::io::ErrorKind::TimedOut =>
+                {
+                    return if deadline.is_none() {
+                        ReadOutcome::Idle
+                    } else {
+                        ReadOutcome::SlowRequest
+                    };
                 }
+                Err(_) => return ReadOutcome::Closed,
+            }
+        }
 
+        let header_end = header_end.unwrap();
+        let head = &buffer[..header_end - 4];
+        let body_kind = body_kind_of(head);
+
+        let (body, body_end) = match body_kind {
+            BodyKind::Length(n) => (buffer[header_end..header_end + n].to_vec(), header_end + n),
+            BodyKind::Chunked => match decode_chunked(&buffer[header_end..]) {
+                Some((body, consumed)) => (body, header_end + consumed),
+                None => (Vec::new(), buffer.len()),
+            },
+            BodyKind::None => (Vec::new(), header_end),
+        };
+        let leftover = buffer[body_end..].to_vec();
+
+        match Request::from_str(&String::from_utf8_lossy(head)) {
+            Some(mut request) => {
+                request.body = body;
+                ReadOutcome::Request(request, leftover)
+            }
+            None => ReadOutcome::Closed,
+        }
+    }
 
-                stream.write(response_request.as_bytes()).unwrap();
-                stream.flush().unwrap();
+    /// Whether `body` (the bytes read so far past the header block) already
+    /// satisfies what `head` declared it would contain.
+    fn body_complete(head: &[u8], body: &[u8]) -> bool {
+        match body_kind_of(head) {
+            BodyKind::Length(n) => body.len() >= n,
+            BodyKind::Chunked => decode_chunked(body).is_some(),
+            BodyKind::None => true,
+        }
+    }
 
-                info!("Request from {}: {} - {}", stream.local_addr().unwrap(), request.uri, status_code.text());
-                return;
+    fn dispatch(
+        stream: &mut TcpStream,
+        routes: &'static [Route],
+        request: &mut Request,
+        keep_alive: bool,
+        middlewares: &[Box<dyn Middleware + Send + Sync>],
+    ) {
+        let mut response = None;
+
+        for middleware in middlewares {
+            if let Some(short_circuit) = middleware.before(request) {
+                response = Some(short_circuit);
+                break;
+            }
+        }
+
+        let mut response = response.unwrap_or_else(|| WebSrv::route(routes, request));
+
+        for middleware in middlewares.iter().rev() {
+            middleware.after(request, &mut response);
+        }
+
+        let status_code = response.status;
+        WebSrv::write_response(stream, &response, keep_alive);
+        info!("Request from {}: {} - {}", stream.local_addr().unwrap(), request.uri, status_code.text());
+    }
+
+    fn route(routes: &'static [Route], request: &mut Request) -> HttpResponse {
+        for route in routes {
+            if request.method != route.method {
+                continue;
+            }
+
+            if let Some(params) = route.match_params(&request.uri) {
+                request.params = params;
+                return (route.func)(request);
             }
         }
 
         // Route not found
         let result = std::fs::read_to_string("templates/404.html").unwrap();
-        let result = format!("HTTP/1.1 404 Not Found\r\nContent-Length: {}\r\n\r\n{}", result.len(), result);
-        stream.write(result.as_bytes()).unwrap();
+        HttpResponse::with_status(StatusCode::NotFound).body(result)
+    }
+
+    fn write_response(stream: &mut TcpStream, response: &HttpResponse, keep_alive: bool) {
+        let status_code = response.status;
+        let body = response.body.as_str();
+        let mut out = String::new();
+
+        out.push_str(format!("HTTP/1.1 {}\r\n", status_code.text()).as_str());
+        out.push_str(format!("Connection: {}\r\n", if keep_alive { "keep-alive" } else { "close" }).as_str());
+
+        for (key, value) in &response.headers {
+            out.push_str(format!("{}: {}\r\n", key, value).as_str());
+        }
+
+        // 204/304 must never carry a body, and there's nothing to announce
+        // a length for when the body is empty either way.
+        let omit_body = matches!(status_code, StatusCode::NoContent | StatusCode::NotModified) || body.is_empty();
+
+        if omit_body {
+            out.push_str("\r\n");
+        } else {
+            out.push_str(format!("Content-Length: {}\r\n\r\n", body.len()).as_str());
+            out.push_str(body);
+        }
+
+        stream.write(out.as_bytes()).unwrap();
         stream.flush().unwrap();
-        info!("Request from {}: {} - {}", stream.local_addr().unwrap(), request.uri, StatusCode::NotFound.text());
     }
 }
 
@@ -166,43 +480,86 @@ pub struct Request {
     pub method: Method,
     pub uri: String,
     pub headers: Vec<Header>,
-    pub body: String,
+    pub body: Vec<u8>,
+    pub params: HashMap<String, String>,
+    pub query: HashMap<String, String>,
 }
 
 impl Request {
-    fn from_str(body: &std::borrow::Cow<'_, str>) -> Option<Request> {
-        let mut lines = body.lines();
+    /// Parses the request line and headers out of `head`, the header block
+    /// with its terminating blank line already stripped. The body is left
+    /// empty; the caller fills it in once it knows how much to read.
+    fn from_str(head: &str) -> Option<Request> {
+        let mut lines = head.lines();
         let mut firstline = lines.next().unwrap().split_whitespace();
         let method = Method::from_str(firstline.next());
         if method.is_none() {
             return None;
         }
         let method = method.unwrap();
-        let uri = firstline.next().unwrap();
+        let (uri, query) = Request::split_query(firstline.next().unwrap());
         let http_ver = firstline.next().unwrap();
         let mut headers = vec![];
 
-        let mut body = String::new();
-        let mut parsing_headers = true;
         for line in lines {
-            if line.chars().next().is_none() {
-                parsing_headers = false;
-                continue;
-            }
+            let mut kv = line.split(": ");
+            headers.push(Header::new(kv.next().unwrap(), kv.next().unwrap()));
+        }
 
-            match parsing_headers {
-                true => {
-                    let mut kv = line.split(": ");
-                    headers.push(Header::new(kv.next().unwrap(), kv.next().unwrap()));
-                },
-                false => {
-                    body.push_str(line);
+        Some(Request {
+            http_ver: String::from(http_ver),
+            method,
+            uri,
+            headers,
+            body: Vec::new(),
+            params: HashMap::new(),
+            query,
+        })
+    }
+
+    /// Returns the body decoded as UTF-8, replacing invalid sequences.
+    pub fn body_str(&self) -> std::borrow::Cow<'_, str> {
+        String::from_utf8_lossy(&self.body)
+    }
+
+    /// Splits a request target like `/users/5?active=true` into its path
+    /// and a map of query parameters.
+    fn split_query(target: &str) -> (String, HashMap<String, String>) {
+        let mut parts = target.splitn(2, '?');
+        let path = String::from(parts.next().unwrap());
+        let mut query = HashMap::new();
+
+        if let Some(query_string) = parts.next() {
+            for pair in query_string.split('&') {
+                let mut kv = pair.splitn(2, '=');
+                let key = kv.next().unwrap_or("");
+                if key.is_empty() {
+                    continue;
                 }
+                query.insert(String::from(key), String::from(kv.next().unwrap_or("")));
             }
-
         }
 
-        Some(Request {http_ver: String::from(http_ver), method, uri: String::from(uri), headers, body})
+        (path, query)
+    }
+
+    /// Looks up a header by name, case-insensitively.
+    pub fn header(&self, key: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|h| h.key.eq_ignore_ascii_case(key))
+            .map(|h| h.value.as_str())
+    }
+
+    /// Whether the connection should stay open for another request once
+    /// this one has been served, per the HTTP/1.1 keep-alive defaults and
+    /// the client's `Connection` header.
+    fn wants_keep_alive(&self) -> bool {
+        match self.header("Connection").map(|v| v.to_ascii_lowercase()) {
+            Some(value) if value == "close" => false,
+            Some(value) if value == "keep-alive" => true,
+            _ => self.http_ver == "HTTP/1.1",
+        }
     }
 }
 
@@ -221,25 +578,56 @@ impl Header {
 pub struct Route {
     pub path: &'static str,
     pub method: Method,
-    pub func: fn(request: &Request) -> (StatusCode, String),
+    pub func: fn(request: &Request) -> HttpResponse,
 }
 
 impl Route {
-    pub fn new(path: &'static str, method: Method, func: fn(request: &Request) -> (StatusCode, String)) -> Route {
+    pub fn new(path: &'static str, method: Method, func: fn(request: &Request) -> HttpResponse) -> Route {
         Route { path, method, func }
     }
+
+    /// Matches `uri` against this route's path pattern, binding `:name`
+    /// segments and a trailing `*name` wildcard into a params map.
+    /// Returns `None` if `uri` doesn't fit the pattern.
+    fn match_params(&self, uri: &str) -> Option<HashMap<String, String>> {
+        let mut params = HashMap::new();
+        let mut pattern_segments = self.path.trim_matches('/').split('/').peekable();
+        let mut uri_segments = uri.trim_matches('/').split('/').peekable();
+
+        loop {
+            match (pattern_segments.next(), uri_segments.peek()) {
+                (None, None) => return Some(params),
+                (None, Some(_)) => return None,
+                (Some(segment), _) if segment.starts_with('*') => {
+                    let rest: Vec<&str> = uri_segments.collect();
+                    params.insert(String::from(&segment[1..]), rest.join("/"));
+                    return Some(params);
+                }
+                (Some(segment), Some(_)) if segment.starts_with(':') => {
+                    let value = uri_segments.next().unwrap();
+                    params.insert(String::from(&segment[1..]), String::from(value));
+                }
+                (Some(segment), Some(value)) if segment == *value => {
+                    uri_segments.next();
+                }
+                _ => return None,
+            }
+        }
+    }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy)]
 pub enum StatusCode {
     Ok = 200,
     Created = 201,
     Accepted = 202,
     NoContent = 204,
+    NotModified = 304,
     BadRequest = 400,
     Unauthorized = 401,
     Forbidden = 403,
     NotFound = 404,
+    RequestTimeout = 408,
 }
 
 impl StatusCode {
@@ -249,10 +637,12 @@ impl StatusCode {
             StatusCode::Created => "201 Created",
             StatusCode::Accepted => "202 Accepted",
             StatusCode::NoContent => "204 No Content",
+            StatusCode::NotModified => "304 Not Modified",
             StatusCode::BadRequest => "400 Bad Request",
             StatusCode::Unauthorized => "401 Unauthorized",
             StatusCode::Forbidden => "403 Forbidden",
             StatusCode::NotFound => "404 Not Found",
+            StatusCode::RequestTimeout => "408 Request Timeout",
         }
     }
 }
@@ -264,7 +654,8 @@ pub enum Method {
     POST,
     PUT,
     PATCH,
-    DELETE
+    DELETE,
+    OPTIONS
 }
 
 impl Method {
@@ -275,6 +666,7 @@ impl Method {
             Some("PUT") => Some(Method::PUT),
             Some("PATCH") => Some(Method::PATCH),
             Some("DELETE") => Some(Method::DELETE),
+            Some("OPTIONS") => Some(Method::OPTIONS),
             _ => None
         }
     }
@@ -0,0 +1,168 @@
+use std::collections::HashMap;
+use std::io::prelude::*;
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use crate::{Middleware, Route, ThreadPool, WebSrv};
+
+/// An in-process server bound to an ephemeral port, for exercising routes
+/// from tests without hand-rolling sockets. Serves `routes` on a background
+/// thread until dropped.
+pub struct TestServer {
+    addr: SocketAddr,
+    shutdown: Arc<AtomicBool>,
+    listener_thread: Option<thread::JoinHandle<()>>,
+}
+
+impl TestServer {
+    pub fn start(routes: &'static [Route]) -> TestServer {
+        TestServer::start_with(routes, Vec::new())
+    }
+
+    /// Like `start`, but also runs `middlewares` around every request, so
+    /// middleware behavior (CORS, auth, ...) can be exercised the same way
+    /// routes are.
+    pub fn start_with(
+        routes: &'static [Route],
+        middlewares: Vec<Box<dyn Middleware + Send + Sync>>,
+    ) -> TestServer {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        listener.set_nonblocking(true).unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let shutdown = Arc::new(AtomicBool::new(false));
+        let shutdown_signal = Arc::clone(&shutdown);
+        let middlewares = Arc::new(middlewares);
+
+        let listener_thread = thread::spawn(move || {
+            let pool = ThreadPool::new(4);
+
+            for stream in listener.incoming() {
+                if shutdown_signal.load(Ordering::SeqCst) {
+                    break;
+                }
+
+                match stream {
+                    Ok(stream) => {
+                        stream.set_nonblocking(false).unwrap();
+                        let middlewares = Arc::clone(&middlewares);
+                        pool.execute(move || {
+                            WebSrv::handle_connection(stream, routes, Duration::from_secs(5), middlewares);
+                        });
+                    }
+                    Err(ref e) if e.kind() == std::io::ErrorKind::WouldBlock => {
+                        thread::sleep(Duration::from_millis(10));
+                    }
+                    Err(_) => break,
+                }
+            }
+
+            // Dropping `pool` here terminates and joins its workers.
+        });
+
+        TestServer { addr, shutdown, listener_thread: Some(listener_thread) }
+    }
+
+    pub fn base_url(&self) -> String {
+        format!("http://{}", self.addr)
+    }
+
+    /// The ephemeral address this server is bound to, for tests that need a
+    /// raw connection of their own (e.g. to exercise keep-alive over one
+    /// retained socket rather than `get`/`post`'s one-request-per-connection
+    /// shorthand).
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    pub fn get(&self, path: &str) -> TestResponse {
+        self.request("GET", path, &[], None)
+    }
+
+    pub fn post(&self, path: &str, body: &str) -> TestResponse {
+        self.request("POST", path, &[], Some(body))
+    }
+
+    /// Sends a request with arbitrary method and headers, e.g. an `OPTIONS`
+    /// preflight with an `Origin` header.
+    pub fn send(&self, method: &str, path: &str, headers: &[(&str, &str)]) -> TestResponse {
+        self.request(method, path, headers, None)
+    }
+
+    fn request(&self, method: &str, path: &str, headers: &[(&str, &str)], body: Option<&str>) -> TestResponse {
+        let mut stream = TcpStream::connect(self.addr).unwrap();
+
+        let mut request = format!("{} {} HTTP/1.1\r\nHost: {}\r\nConnection: close\r\n", method, path, self.addr);
+        for (key, value) in headers {
+            request.push_str(format!("{}: {}\r\n", key, value).as_str());
+        }
+        match body {
+            Some(body) => {
+                request.push_str(format!("Content-Length: {}\r\n\r\n", body.len()).as_str());
+                request.push_str(body);
+            }
+            None => request.push_str("\r\n"),
+        }
+
+        stream.write_all(request.as_bytes()).unwrap();
+        stream.flush().unwrap();
+
+        let mut raw = Vec::new();
+        stream.read_to_end(&mut raw).unwrap();
+
+        TestResponse::parse(&raw)
+    }
+}
+
+impl Drop for TestServer {
+    fn drop(&mut self) {
+        // The accept loop polls this flag every 10ms, so it notices the
+        // shutdown request shortly after it's set.
+        self.shutdown.store(true, Ordering::SeqCst);
+
+        if let Some(thread) = self.listener_thread.take() {
+            thread.join().unwrap();
+        }
+    }
+}
+
+/// A parsed response from a `TestServer` request.
+pub struct TestResponse {
+    pub status: u16,
+    pub headers: HashMap<String, String>,
+    pub body: String,
+}
+
+impl TestResponse {
+    fn parse(raw: &[u8]) -> TestResponse {
+        let header_end = raw
+            .windows(4)
+            .position(|w| w == b"\r\n\r\n")
+            .map(|i| i + 4)
+            .unwrap_or(raw.len());
+
+        let head = String::from_utf8_lossy(&raw[..header_end]);
+        let mut lines = head.lines();
+
+        let status = lines
+            .next()
+            .and_then(|line| line.split_whitespace().nth(1))
+            .and_then(|code| code.parse().ok())
+            .unwrap_or(0);
+
+        let mut headers = HashMap::new();
+        for line in lines {
+            let mut kv = line.splitn(2, ": ");
+            if let (Some(key), Some(value)) = (kv.next(), kv.next()) {
+                headers.insert(String::from(key), String::from(value));
+            }
+        }
+
+        let body = String::from_utf8_lossy(&raw[header_end..]).to_string();
+
+        TestResponse { status, headers, body }
+    }
+}
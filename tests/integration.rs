@@ -0,0 +1,130 @@
+use std::io::{Read, Write};
+use std::net::TcpStream;
+
+use websrv::cors::Cors;
+use websrv::test_server::TestServer;
+use websrv::{HttpResponse, Method, Middleware, Request, Route, StatusCode};
+
+static ROUTES: &'static [Route] = &[
+    Route { path: "/users/:id", method: Method::GET, func: show_user },
+    Route { path: "/files/*rest", method: Method::GET, func: show_file },
+    Route { path: "/no-content", method: Method::GET, func: no_content },
+    Route { path: "/not-modified", method: Method::GET, func: not_modified },
+];
+
+fn show_user(request: &Request) -> HttpResponse {
+    HttpResponse::with_status(StatusCode::Ok).body(request.params.get("id").unwrap().clone())
+}
+
+fn show_file(request: &Request) -> HttpResponse {
+    HttpResponse::with_status(StatusCode::Ok).body(request.params.get("rest").unwrap().clone())
+}
+
+fn no_content(_: &Request) -> HttpResponse {
+    HttpResponse::with_status(StatusCode::NoContent)
+}
+
+fn not_modified(_: &Request) -> HttpResponse {
+    HttpResponse::with_status(StatusCode::NotModified)
+}
+
+#[test]
+fn named_param_and_wildcard_routes_match() {
+    let server = TestServer::start(ROUTES);
+
+    let by_id = server.get("/users/42");
+    assert_eq!(by_id.status, 200);
+    assert_eq!(by_id.body, "42");
+
+    let by_path = server.get("/files/a/b/c.txt");
+    assert_eq!(by_path.status, 200);
+    assert_eq!(by_path.body, "a/b/c.txt");
+}
+
+#[test]
+fn bodiless_statuses_omit_body_and_content_length() {
+    let server = TestServer::start(ROUTES);
+
+    for path in ["/no-content", "/not-modified"] {
+        let response = server.get(path);
+        assert!(response.body.is_empty());
+        assert!(!response.headers.contains_key("Content-Length"));
+    }
+}
+
+#[test]
+fn cors_preflight_echoes_allowed_origin_once() {
+    let cors = Cors::new()
+        .allow_origin("http://allowed.test")
+        .allow_method("GET")
+        .allow_header("Content-Type");
+    let middlewares: Vec<Box<dyn Middleware + Send + Sync>> = vec![Box::new(cors)];
+    let server = TestServer::start_with(ROUTES, middlewares);
+
+    let response = server.send("OPTIONS", "/users/42", &[("Origin", "http://allowed.test")]);
+
+    assert_eq!(response.status, 204);
+    assert!(response.body.is_empty());
+    assert_eq!(
+        response.headers.get("Access-Control-Allow-Origin"),
+        Some(&String::from("http://allowed.test"))
+    );
+    assert_eq!(response.headers.get("Access-Control-Allow-Methods"), Some(&String::from("GET")));
+}
+
+#[test]
+fn keep_alive_serves_two_requests_on_one_connection() {
+    let server = TestServer::start(ROUTES);
+    let mut stream = TcpStream::connect(server.addr()).unwrap();
+
+    write!(stream, "GET /users/1 HTTP/1.1\r\nHost: {}\r\n\r\n", server.addr()).unwrap();
+    let (status, body) = read_one_response(&mut stream);
+    assert_eq!(status, 200);
+    assert_eq!(body, "1");
+
+    write!(stream, "GET /users/2 HTTP/1.1\r\nHost: {}\r\nConnection: close\r\n\r\n", server.addr()).unwrap();
+    let (status, body) = read_one_response(&mut stream);
+    assert_eq!(status, 200);
+    assert_eq!(body, "2");
+}
+
+/// Reads one HTTP response off `stream` (headers plus however much of the
+/// body `Content-Length` declares). It reads in 1024-byte chunks, so any
+/// bytes past that response which arrive in the same read are discarded.
+fn read_one_response(stream: &mut TcpStream) -> (u16, String) {
+    let mut buf = Vec::new();
+    let mut chunk = [0u8; 1024];
+
+    let header_end = loop {
+        let n = stream.read(&mut chunk).unwrap();
+        assert!(n > 0, "connection closed before a full response arrived");
+        buf.extend_from_slice(&chunk[..n]);
+
+        if let Some(pos) = buf.windows(4).position(|w| w == b"\r\n\r\n") {
+            break pos + 4;
+        }
+    };
+
+    let head = String::from_utf8_lossy(&buf[..header_end]).to_string();
+    let mut lines = head.lines();
+    let status = lines.next().unwrap().split_whitespace().nth(1).unwrap().parse().unwrap();
+    let content_length: usize = lines
+        .find_map(|line| {
+            let mut kv = line.splitn(2, ": ");
+            if kv.next()?.eq_ignore_ascii_case("Content-Length") {
+                kv.next()?.trim().parse().ok()
+            } else {
+                None
+            }
+        })
+        .unwrap_or(0);
+
+    while buf.len() < header_end + content_length {
+        let n = stream.read(&mut chunk).unwrap();
+        assert!(n > 0, "connection closed before the full body arrived");
+        buf.extend_from_slice(&chunk[..n]);
+    }
+
+    let body = String::from_utf8_lossy(&buf[header_end..header_end + content_length]).to_string();
+    (status, body)
+}